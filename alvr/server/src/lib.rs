@@ -2,6 +2,9 @@ mod buttons;
 mod connection;
 mod dashboard;
 mod logging_backend;
+mod overclock;
+mod preview;
+mod recorder;
 mod sockets;
 mod statistics;
 mod tracking;
@@ -66,6 +69,51 @@ pub struct VideoPacket {
     pub payload: Vec<u8>,
 }
 
+/// Pixel format negotiated for the encoded bitstream. `P010` carries 10-bit 4:2:0 samples
+/// (FourCC 808530000) as produced by HEVC/AV1 Main10 hardware encoders and is only selected
+/// when the client advertises HDR capability during the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorFormat {
+    Nv12,
+    P010,
+}
+
+impl ColorFormat {
+    pub fn bit_depth(self) -> u8 {
+        match self {
+            ColorFormat::Nv12 => 8,
+            ColorFormat::P010 => 10,
+        }
+    }
+}
+
+impl From<u32> for ColorFormat {
+    fn from(raw: u32) -> Self {
+        match raw {
+            1 => ColorFormat::P010,
+            _ => ColorFormat::Nv12,
+        }
+    }
+}
+
+#[cfg(test)]
+mod color_format_tests {
+    use super::ColorFormat;
+
+    #[test]
+    fn raw_one_is_p010_at_10_bits() {
+        assert_eq!(ColorFormat::from(1), ColorFormat::P010);
+        assert_eq!(ColorFormat::from(1).bit_depth(), 10);
+    }
+
+    #[test]
+    fn unrecognized_raw_values_fall_back_to_nv12_at_8_bits() {
+        assert_eq!(ColorFormat::from(0), ColorFormat::Nv12);
+        assert_eq!(ColorFormat::from(99), ColorFormat::Nv12);
+        assert_eq!(ColorFormat::from(0).bit_depth(), 8);
+    }
+}
+
 static CONTROL_CHANNEL_SENDER: Lazy<Mutex<Option<mpsc::UnboundedSender<ServerControlPacket>>>> =
     Lazy::new(|| Mutex::new(None));
 static VIDEO_SENDER: Lazy<Mutex<Option<mpsc::UnboundedSender<VideoPacket>>>> =
@@ -74,6 +122,18 @@ static HAPTICS_SENDER: Lazy<Mutex<Option<mpsc::UnboundedSender<Haptics>>>> =
     Lazy::new(|| Mutex::new(None));
 static VIDEO_MIRROR_SENDER: Lazy<Mutex<Option<broadcast::Sender<Vec<u8>>>>> =
     Lazy::new(|| Mutex::new(None));
+static RECORDING_STOP_SENDER: Lazy<Mutex<Option<tokio::sync::oneshot::Sender<()>>>> =
+    Lazy::new(|| Mutex::new(None));
+// The most recent `InitializeDecoder` config buffer (SPS/PPS, plus VPS for HEVC). `start_recording`
+// reads this directly instead of waiting on the next `VIDEO_MIRROR_SENDER` broadcast: recording is
+// started well after the stream's one-time config broadcast already went out, and `broadcast`
+// doesn't replay past messages to a subscriber that joins late.
+static LAST_DECODER_CONFIG: Lazy<Mutex<Option<Vec<u8>>>> = Lazy::new(|| Mutex::new(None));
+// Downscaled JPEG frames decoded from VIDEO_MIRROR_SENDER, streamed to the dashboard over the
+// existing websocket layer in `web_server` so users can verify streaming quality without
+// donning the headset.
+static PREVIEW_FRAME_SENDER: Lazy<broadcast::Sender<Vec<u8>>> =
+    Lazy::new(|| broadcast::channel(web_server::WS_BROADCAST_CAPACITY).0);
 
 static DISCONNECT_CLIENT_NOTIFIER: Lazy<Notify> = Lazy::new(Notify::new);
 static RESTART_NOTIFIER: Lazy<Notify> = Lazy::new(Notify::new);
@@ -85,6 +145,10 @@ static COMPRESS_AXIS_ALIGNED_CSO: &[u8] =
     include_bytes!("../cpp/platform/win32/CompressAxisAlignedPixelShader.cso");
 static COLOR_CORRECTION_CSO: &[u8] =
     include_bytes!("../cpp/platform/win32/ColorCorrectionPixelShader.cso");
+// BT.709 -> BT.2020 PQ variant, selected at runtime instead of COLOR_CORRECTION_CSO when the
+// client negotiated HDR (see `select_color_correction_shader`).
+static COLOR_CORRECTION_HDR_CSO: &[u8] =
+    include_bytes!("../cpp/platform/win32/ColorCorrectionHdrPixelShader.cso");
 
 static QUAD_SHADER_VERT_SPV: &[u8] = include_bytes!("../cpp/platform/linux/shader/quad.vert.spv");
 static QUAD_SHADER_FRAG_SPV: &[u8] = include_bytes!("../cpp/platform/linux/shader/quad.frag.spv");
@@ -93,6 +157,39 @@ static FFR_SHADER_FRAG_SPV: &[u8] = include_bytes!("../cpp/platform/linux/shader
 
 static IS_ALIVE: Lazy<Arc<RelaxedAtomic>> = Lazy::new(|| Arc::new(RelaxedAtomic::new(false)));
 
+// Defaults to false: no client has advertised HDR support until `set_client_hdr_capability` is
+// called. That function is meant to be called from the handshake once the client's capability
+// bits are parsed; read from `is_hdr_active`, which the compositor calls per-frame to choose
+// between COLOR_CORRECTION_CSO and COLOR_CORRECTION_HDR_CSO.
+static CLIENT_HDR_ENABLED: Lazy<Arc<RelaxedAtomic>> =
+    Lazy::new(|| Arc::new(RelaxedAtomic::new(false)));
+
+/// Records whether the connected client advertised HDR capability during the handshake. Only
+/// once this is true *and* the user opted in via the `enable_hdr` session setting does
+/// `is_hdr_active` report the HDR shader/bitstream path as active, so non-HDR clients always
+/// keep getting the SDR path regardless of the server-side setting.
+pub fn set_client_hdr_capability(enabled: bool) {
+    CLIENT_HDR_ENABLED.set(enabled);
+}
+
+/// FFmpeg `AVCodecID` for the video codec negotiated with the connected client. Defaults to
+/// H.264 until `set_negotiated_codec` is called from the handshake, since that's the one every
+/// client is guaranteed to support.
+pub const AV_CODEC_ID_H264: i32 = 27;
+pub const AV_CODEC_ID_HEVC: i32 = 173;
+
+static NEGOTIATED_CODEC: Lazy<Mutex<i32>> = Lazy::new(|| Mutex::new(AV_CODEC_ID_H264));
+
+/// Called from the handshake once the codec is negotiated. Read by `preview`/`recorder` so
+/// they decode/mux the stream the client is actually receiving instead of assuming H.264.
+pub fn set_negotiated_codec(codec_id: i32) {
+    *NEGOTIATED_CODEC.lock() = codec_id;
+}
+
+pub fn negotiated_codec() -> i32 {
+    *NEGOTIATED_CODEC.lock()
+}
+
 pub enum WindowType {
     Alcro(alcro::UI),
     Browser,
@@ -145,6 +242,19 @@ pub fn shutdown_runtimes() {
     // Shutsdown all connection runtimes
     IS_ALIVE.set(false);
 
+    if matches!(
+        SERVER_DATA_MANAGER
+            .read()
+            .session()
+            .session_settings
+            .extra
+            .patches
+            .nvidia_clock_boost_settings,
+        alvr_session::Switch::Enabled(_)
+    ) {
+        overclock::restore_clocks(&FILESYSTEM_LAYOUT);
+    }
+
     if let Some(window_type) = WINDOW.lock().take() {
         match window_type.as_ref() {
             WindowType::Alcro(window) => window.close(),
@@ -152,9 +262,73 @@ pub fn shutdown_runtimes() {
         }
     }
 
+    stop_recording();
+
     WEBSERVER_RUNTIME.lock().take();
 }
 
+/// Starts muxing the mirror bitstream to `output_path`. A no-op if a recording is already in
+/// progress. Exposed to `web_server` so recording can be toggled from the dashboard.
+///
+/// `codec_id`/`width`/`height` identify the negotiated video stream (an FFmpeg `AVCodecID`
+/// and the client's requested render resolution) and are attached to the container's codec
+/// parameters alongside the decoder config extradata.
+pub fn start_recording(
+    output_path: std::path::PathBuf,
+    codec_id: i32,
+    width: i32,
+    height: i32,
+    target_framerate: f32,
+) {
+    if RECORDING_STOP_SENDER.lock().is_some() {
+        warn!("A recording is already in progress");
+        return;
+    }
+
+    let Some(mirror_sender) = &*VIDEO_MIRROR_SENDER.lock() else {
+        warn!("Cannot start recording: no active streaming session");
+        return;
+    };
+    let mirror_receiver = mirror_sender.subscribe();
+
+    // Recording starts well after the stream's decoder config was broadcast, so it can't be
+    // recovered by subscribing to VIDEO_MIRROR_SENDER and waiting for the next message: fetch the
+    // cached copy instead.
+    let Some(extradata) = latest_decoder_config() else {
+        warn!("Cannot start recording: no decoder config negotiated yet");
+        return;
+    };
+
+    let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel();
+    *RECORDING_STOP_SENDER.lock() = Some(stop_sender);
+
+    if let Some(runtime) = WEBSERVER_RUNTIME.lock().as_mut() {
+        runtime.spawn(recorder::recording_loop(
+            output_path,
+            codec_id,
+            width,
+            height,
+            extradata,
+            target_framerate,
+            mirror_receiver,
+            stop_receiver,
+        ));
+    }
+}
+
+/// Returns the last `InitializeDecoder` config buffer seen, if any client has connected since
+/// the driver started.
+pub fn latest_decoder_config() -> Option<Vec<u8>> {
+    LAST_DECODER_CONFIG.lock().clone()
+}
+
+/// Stops the in-progress recording, if any, flushing and finalizing the container trailer.
+pub fn stop_recording() {
+    if let Some(stop_sender) = RECORDING_STOP_SENDER.lock().take() {
+        stop_sender.send(()).ok();
+    }
+}
+
 pub fn notify_shutdown_driver() {
     thread::spawn(|| {
         RESTART_NOTIFIER.notify_waiters();
@@ -215,6 +389,12 @@ fn init() {
         )));
 
         thread::spawn(|| alvr_common::show_err(dashboard::ui_thread()));
+
+        let (mirror_sender, _) = broadcast::channel(web_server::WS_BROADCAST_CAPACITY);
+        let mirror_receiver = mirror_sender.subscribe();
+        *VIDEO_MIRROR_SENDER.lock() = Some(mirror_sender);
+
+        runtime.spawn(preview::preview_loop(mirror_receiver, PREVIEW_FRAME_SENDER.clone()));
     }
 
     {
@@ -271,8 +451,13 @@ pub unsafe extern "C" fn HmdDriverFactory(
     QUAD_SHADER_CSO_LEN = QUAD_SHADER_CSO.len() as _;
     COMPRESS_AXIS_ALIGNED_CSO_PTR = COMPRESS_AXIS_ALIGNED_CSO.as_ptr();
     COMPRESS_AXIS_ALIGNED_CSO_LEN = COMPRESS_AXIS_ALIGNED_CSO.len() as _;
+    // Both color-correction shader variants are always handed to the compositor; which one it
+    // binds per frame is decided at render time by calling back into `is_hdr_active`, since the
+    // client's HDR capability isn't known yet at this point (SteamVR hasn't connected a client).
     COLOR_CORRECTION_CSO_PTR = COLOR_CORRECTION_CSO.as_ptr();
     COLOR_CORRECTION_CSO_LEN = COLOR_CORRECTION_CSO.len() as _;
+    COLOR_CORRECTION_HDR_CSO_PTR = COLOR_CORRECTION_HDR_CSO.as_ptr();
+    COLOR_CORRECTION_HDR_CSO_LEN = COLOR_CORRECTION_HDR_CSO.len() as _;
     QUAD_SHADER_VERT_SPV_PTR = QUAD_SHADER_VERT_SPV.as_ptr();
     QUAD_SHADER_VERT_SPV_LEN = QUAD_SHADER_VERT_SPV.len() as _;
     QUAD_SHADER_FRAG_SPV_PTR = QUAD_SHADER_FRAG_SPV.as_ptr();
@@ -322,7 +507,7 @@ pub unsafe extern "C" fn HmdDriverFactory(
         }
     }
 
-    extern "C" fn initialize_decoder(buffer_ptr: *const u8, len: i32) {
+    extern "C" fn initialize_decoder(buffer_ptr: *const u8, len: i32, color_format: u32) {
         if let Some(sender) = &*CONTROL_CHANNEL_SENDER.lock() {
             let mut config_buffer = vec![0; len as usize];
 
@@ -333,9 +518,13 @@ pub unsafe extern "C" fn HmdDriverFactory(
             if let Some(sender) = &*VIDEO_MIRROR_SENDER.lock() {
                 sender.send(config_buffer.clone()).ok();
             }
+            *LAST_DECODER_CONFIG.lock() = Some(config_buffer.clone());
 
             sender
-                .send(ServerControlPacket::InitializeDecoder { config_buffer })
+                .send(ServerControlPacket::InitializeDecoder {
+                    config_buffer,
+                    color_format: ColorFormat::from(color_format).bit_depth(),
+                })
                 .ok();
         }
     }
@@ -350,6 +539,7 @@ pub unsafe extern "C" fn HmdDriverFactory(
                 frame_byte_size: header.frameByteSize,
                 fec_index: header.fecIndex,
                 fec_percentage: header.fecPercentage,
+                color_format: ColorFormat::from(header.colorFormat),
             };
 
             let mut vec_buffer = vec![0; len as _];
@@ -396,6 +586,22 @@ pub unsafe extern "C" fn HmdDriverFactory(
 
         IS_ALIVE.set(true);
 
+        let clock_boost_settings = SERVER_DATA_MANAGER
+            .read()
+            .session()
+            .session_settings
+            .extra
+            .patches
+            .nvidia_clock_boost_settings
+            .clone();
+        if let alvr_session::Switch::Enabled(settings) = clock_boost_settings {
+            overclock::boost_clocks(
+                &FILESYSTEM_LAYOUT,
+                settings.graphics_clock_offset,
+                settings.memory_transfer_rate_offset,
+            );
+        }
+
         thread::spawn(move || {
             if set_default_chap {
                 // call this when inside a new tokio thread. Calling this on the parent thread will
@@ -441,6 +647,20 @@ pub unsafe extern "C" fn HmdDriverFactory(
         }
     }
 
+    // Called by the compositor each frame to decide whether to bind COLOR_CORRECTION_CSO or
+    // COLOR_CORRECTION_HDR_CSO. True only when the connected client negotiated HDR support
+    // and the user opted in; false (the SDR path) otherwise, including before any client has
+    // connected.
+    extern "C" fn is_hdr_active() -> bool {
+        CLIENT_HDR_ENABLED.value()
+            && SERVER_DATA_MANAGER
+                .read()
+                .session()
+                .session_settings
+                .video
+                .enable_hdr
+    }
+
     LogError = Some(log_error);
     LogWarn = Some(log_warn);
     LogInfo = Some(log_info);
@@ -456,6 +676,7 @@ pub unsafe extern "C" fn HmdDriverFactory(
     ReportComposed = Some(report_composed);
     ReportEncoded = Some(report_encoded);
     ReportFecFailure = Some(report_fec_failure);
+    IsHdrActive = Some(is_hdr_active);
 
     // cast to usize to allow the variables to cross thread boundaries
     let interface_name_usize = interface_name as usize;
@@ -0,0 +1,292 @@
+use alvr_common::prelude::*;
+use alvr_filesystem::Layout;
+use parking_lot::Mutex;
+use std::{
+    ffi::{c_void, CString},
+    fs,
+    os::raw::{c_char, c_int},
+    ptr,
+};
+
+// NV-CONTROL X extension, the interface `nvidia-settings` uses on top of libX11 (requires
+// Coolbits to be enabled in the X config to expose the clock-offset attributes at all).
+mod nvctrl {
+    use super::*;
+
+    pub const NV_CTRL_TARGET_TYPE_GPU: c_int = 1;
+
+    // From NVCtrl.h.
+    pub const NV_CTRL_GPU_NVCLOCK_OFFSET_ALL_PERFORMANCE_LEVELS: c_int = 438;
+    pub const NV_CTRL_GPU_MEM_TRANSFER_RATE_OFFSET_ALL_PERFORMANCE_LEVELS: c_int = 439;
+    pub const NV_CTRL_GPU_POWER_MIZER_MODE: c_int = 321;
+    pub const NV_CTRL_GPU_POWER_MIZER_MODE_PREFER_MAXIMUM_PERFORMANCE: c_int = 1;
+
+    #[repr(C)]
+    pub struct Display {
+        _private: [u8; 0],
+    }
+
+    extern "C" {
+        pub fn XOpenDisplay(name: *const c_char) -> *mut Display;
+        pub fn XCloseDisplay(display: *mut Display) -> c_int;
+
+        pub fn XNVCTRLQueryTargetAttribute(
+            display: *mut Display,
+            target_type: c_int,
+            target_id: c_int,
+            display_mask: c_int,
+            attribute: c_int,
+            value: *mut c_int,
+        ) -> c_int;
+
+        pub fn XNVCTRLSetTargetAttributeAndGetStatus(
+            display: *mut Display,
+            target_type: c_int,
+            target_id: c_int,
+            display_mask: c_int,
+            attribute: c_int,
+            value: c_int,
+        ) -> c_int;
+    }
+}
+
+/// A single open connection to the X server used to issue NV-CONTROL requests. GPU target id 0
+/// is the common case (the NVIDIA driver is also the OpenVR compositor's adapter); multi-GPU
+/// setups aren't addressed here.
+struct NvCtrlHandle {
+    display: *mut nvctrl::Display,
+}
+
+unsafe impl Send for NvCtrlHandle {}
+
+impl NvCtrlHandle {
+    fn open() -> StrResult<Self> {
+        let display = unsafe { nvctrl::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return fmt_e!("Failed to open X display for NV-CONTROL (is Coolbits enabled?)");
+        }
+
+        Ok(Self { display })
+    }
+
+    fn query(&self, attribute: c_int) -> StrResult<i32> {
+        let mut value = 0;
+        let ok = unsafe {
+            nvctrl::XNVCTRLQueryTargetAttribute(
+                self.display,
+                nvctrl::NV_CTRL_TARGET_TYPE_GPU,
+                0,
+                0,
+                attribute,
+                &mut value,
+            )
+        };
+
+        if ok == 0 {
+            return fmt_e!("XNVCTRLQueryTargetAttribute failed for attribute {attribute}");
+        }
+
+        Ok(value)
+    }
+
+    fn set(&self, attribute: c_int, value: i32) -> StrResult {
+        let ok = unsafe {
+            nvctrl::XNVCTRLSetTargetAttributeAndGetStatus(
+                self.display,
+                nvctrl::NV_CTRL_TARGET_TYPE_GPU,
+                0,
+                0,
+                attribute,
+                value,
+            )
+        };
+
+        if ok == 0 {
+            return fmt_e!("XNVCTRLSetTargetAttributeAndGetStatus failed for attribute {attribute}");
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for NvCtrlHandle {
+    fn drop(&mut self) {
+        unsafe { nvctrl::XCloseDisplay(self.display) };
+    }
+}
+
+/// Offsets (and power-mizer mode, since `pin_max_performance_state` touches that too) applied
+/// to the active NVIDIA performance level. Stored to disk before applying so a crash can be
+/// recovered from by restoring the real pre-boost values on the next startup.
+#[derive(Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+struct ClockOffsets {
+    graphics_clock_offset: i32,
+    memory_transfer_rate_offset: i32,
+    // `None` when the board doesn't expose a writable power-mizer attribute, in which case
+    // neither `pin_max_performance_state` nor restoring it attempt to touch it.
+    power_mizer_mode: Option<i32>,
+}
+
+static ORIGINAL_OFFSETS: Mutex<Option<ClockOffsets>> = Mutex::new(None);
+
+fn backup_path(layout: &Layout) -> std::path::PathBuf {
+    layout.session().with_file_name("overclock_backup.json")
+}
+
+/// Raises the GPU clocks for the duration of the streaming session. No-op on non-NVIDIA GPUs
+/// or if Coolbits isn't enabled (the NV-CONTROL calls fail and are logged loudly instead of
+/// silently doing nothing).
+pub fn boost_clocks(layout: &Layout, graphics_clock_offset: i32, memory_transfer_rate_offset: i32) {
+    if let Err(e) = try_restore_from_crash(layout) {
+        warn!("Failed to restore GPU clocks from a previous crash: {e}");
+    }
+
+    let handle = match NvCtrlHandle::open() {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("GPU clock boost unavailable, streaming will run at stock clocks: {e}");
+            return;
+        }
+    };
+
+    let original = match read_current_offsets(&handle) {
+        Ok(original) => {
+            *ORIGINAL_OFFSETS.lock() = Some(original);
+
+            if let Ok(serialized) = serde_json::to_string(&original) {
+                fs::write(backup_path(layout), serialized).ok();
+            }
+
+            original
+        }
+        Err(e) => {
+            error!("Failed to read current GPU clock offsets, not boosting: {e}");
+            return;
+        }
+    };
+
+    if let Err(e) = apply_offsets(
+        &handle,
+        ClockOffsets {
+            graphics_clock_offset,
+            memory_transfer_rate_offset,
+            power_mizer_mode: original.power_mizer_mode,
+        },
+    ) {
+        error!("Failed to apply GPU clock boost: {e}");
+    }
+
+    if let Err(e) = pin_max_performance_state(&handle, original) {
+        error!("Failed to pin GPU to its maximum performance state: {e}");
+    }
+}
+
+/// Restores the clocks that were recorded before `boost_clocks` was called.
+pub fn restore_clocks(layout: &Layout) {
+    let Some(original) = ORIGINAL_OFFSETS.lock().take() else {
+        return;
+    };
+
+    match NvCtrlHandle::open() {
+        Ok(handle) => {
+            if let Err(e) = apply_offsets(&handle, original) {
+                error!("Failed to restore GPU clocks: {e}");
+            }
+        }
+        Err(e) => error!("Failed to restore GPU clocks, original values are lost: {e}"),
+    }
+
+    fs::remove_file(backup_path(layout)).ok();
+}
+
+fn try_restore_from_crash(layout: &Layout) -> StrResult {
+    let path = backup_path(layout);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let serialized = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    let offsets: ClockOffsets = serde_json::from_str(&serialized).map_err(|e| e.to_string())?;
+
+    let handle = NvCtrlHandle::open()?;
+    apply_offsets(&handle, offsets)?;
+
+    fs::remove_file(path).ok();
+
+    Ok(())
+}
+
+fn read_current_offsets(handle: &NvCtrlHandle) -> StrResult<ClockOffsets> {
+    Ok(ClockOffsets {
+        graphics_clock_offset: handle
+            .query(nvctrl::NV_CTRL_GPU_NVCLOCK_OFFSET_ALL_PERFORMANCE_LEVELS)?,
+        memory_transfer_rate_offset: handle
+            .query(nvctrl::NV_CTRL_GPU_MEM_TRANSFER_RATE_OFFSET_ALL_PERFORMANCE_LEVELS)?,
+        // Not every board exposes a writable power-mizer attribute; if the query fails, treat
+        // it as unsupported rather than erroring the whole boost out.
+        power_mizer_mode: handle.query(nvctrl::NV_CTRL_GPU_POWER_MIZER_MODE).ok(),
+    })
+}
+
+fn apply_offsets(handle: &NvCtrlHandle, offsets: ClockOffsets) -> StrResult {
+    handle.set(
+        nvctrl::NV_CTRL_GPU_NVCLOCK_OFFSET_ALL_PERFORMANCE_LEVELS,
+        offsets.graphics_clock_offset,
+    )?;
+    handle.set(
+        nvctrl::NV_CTRL_GPU_MEM_TRANSFER_RATE_OFFSET_ALL_PERFORMANCE_LEVELS,
+        offsets.memory_transfer_rate_offset,
+    )?;
+
+    if let Some(power_mizer_mode) = offsets.power_mizer_mode {
+        handle.set(nvctrl::NV_CTRL_GPU_POWER_MIZER_MODE, power_mizer_mode)?;
+    }
+
+    Ok(())
+}
+
+fn pin_max_performance_state(handle: &NvCtrlHandle, original: ClockOffsets) -> StrResult {
+    if original.power_mizer_mode.is_none() {
+        return Ok(());
+    }
+
+    handle.set(
+        nvctrl::NV_CTRL_GPU_POWER_MIZER_MODE,
+        nvctrl::NV_CTRL_GPU_POWER_MIZER_MODE_PREFER_MAXIMUM_PERFORMANCE,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_offsets_round_trip_through_json() {
+        let offsets = ClockOffsets {
+            graphics_clock_offset: 150,
+            memory_transfer_rate_offset: 400,
+            power_mizer_mode: Some(0),
+        };
+
+        let serialized = serde_json::to_string(&offsets).unwrap();
+        let deserialized: ClockOffsets = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(deserialized.graphics_clock_offset, 150);
+        assert_eq!(deserialized.memory_transfer_rate_offset, 400);
+        assert_eq!(deserialized.power_mizer_mode, Some(0));
+    }
+
+    #[test]
+    fn unsupported_power_mizer_mode_round_trips_as_none() {
+        let offsets = ClockOffsets {
+            graphics_clock_offset: 0,
+            memory_transfer_rate_offset: 0,
+            power_mizer_mode: None,
+        };
+
+        let serialized = serde_json::to_string(&offsets).unwrap();
+        let deserialized: ClockOffsets = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(deserialized.power_mizer_mode, None);
+    }
+}
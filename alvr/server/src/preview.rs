@@ -0,0 +1,340 @@
+use alvr_common::prelude::*;
+use std::{
+    os::raw::{c_int, c_void},
+    ptr,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+// Hand-declared subset of libavcodec/libavutil/libswscale, following the same minimal-FFI
+// style as `recorder`'s `av` module: only the entry points this file actually calls.
+#[allow(non_camel_case_types)]
+mod av {
+    use super::*;
+
+    pub const AV_HWDEVICE_TYPE_CUDA: c_int = 1;
+    pub const AV_HWDEVICE_TYPE_VAAPI: c_int = 2;
+    pub const AV_PIX_FMT_RGB24: c_int = 2;
+    pub const SWS_BILINEAR: c_int = 2;
+
+    /// Hardware backends to try, in order, for the negotiated codec. The first one whose
+    /// `av_hwdevice_ctx_create` call succeeds is used; if none do, preview falls back to
+    /// software decode.
+    pub const HW_DEVICE_TYPES_IN_PRIORITY_ORDER: [c_int; 2] =
+        [AV_HWDEVICE_TYPE_CUDA, AV_HWDEVICE_TYPE_VAAPI];
+
+    #[repr(C)]
+    pub struct AVCodecContext {
+        pub hw_device_ctx: *mut AVBufferRef,
+        pub extradata: *mut u8,
+        pub extradata_size: c_int,
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct AVCodec {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct AVPacket {
+        pub data: *mut u8,
+        pub size: c_int,
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct AVFrame {
+        pub width: c_int,
+        pub height: c_int,
+        pub format: c_int,
+        pub data: [*mut u8; 8],
+        pub linesize: [c_int; 8],
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct AVBufferRef {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct SwsContext {
+        _private: [u8; 0],
+    }
+
+    extern "C" {
+        pub fn avcodec_find_decoder(id: c_int) -> *const AVCodec;
+        pub fn avcodec_alloc_context3(codec: *const AVCodec) -> *mut AVCodecContext;
+        pub fn avcodec_open2(
+            ctx: *mut AVCodecContext,
+            codec: *const AVCodec,
+            options: *mut *mut c_void,
+        ) -> c_int;
+        pub fn avcodec_send_packet(ctx: *mut AVCodecContext, pkt: *const AVPacket) -> c_int;
+        pub fn avcodec_receive_frame(ctx: *mut AVCodecContext, frame: *mut AVFrame) -> c_int;
+        pub fn av_hwdevice_ctx_create(
+            device_ctx: *mut *mut AVBufferRef,
+            device_type: c_int,
+            device: *const std::os::raw::c_char,
+            opts: *mut *mut c_void,
+            flags: c_int,
+        ) -> c_int;
+        pub fn av_buffer_ref(buf: *mut AVBufferRef) -> *mut AVBufferRef;
+        pub fn av_buffer_unref(buf: *mut *mut AVBufferRef);
+        pub fn av_hwframe_transfer_data(dst: *mut AVFrame, src: *const AVFrame, flags: c_int) -> c_int;
+        pub fn av_frame_alloc() -> *mut AVFrame;
+        pub fn av_frame_free(frame: *mut *mut AVFrame);
+        pub fn av_packet_alloc() -> *mut AVPacket;
+        pub fn av_packet_free(pkt: *mut *mut AVPacket);
+        pub fn av_malloc(size: usize) -> *mut c_void;
+
+        pub fn sws_getContext(
+            src_w: c_int,
+            src_h: c_int,
+            src_format: c_int,
+            dst_w: c_int,
+            dst_h: c_int,
+            dst_format: c_int,
+            flags: c_int,
+            src_filter: *mut c_void,
+            dst_filter: *mut c_void,
+            param: *const f64,
+        ) -> *mut SwsContext;
+        pub fn sws_scale(
+            ctx: *mut SwsContext,
+            src_slice: *const *const u8,
+            src_stride: *const c_int,
+            src_slice_y: c_int,
+            src_slice_h: c_int,
+            dst_slice: *const *mut u8,
+            dst_stride: *const c_int,
+        ) -> c_int;
+        pub fn sws_freeContext(ctx: *mut SwsContext);
+    }
+}
+
+const THROTTLE_INTERVAL: Duration = Duration::from_millis(200); // a few FPS, enough to eyeball quality
+const PREVIEW_WIDTH: i32 = 480;
+
+/// Copies the `InitializeDecoder` config buffer (SPS/PPS, plus VPS for HEVC) onto the decoder
+/// context's `extradata`, the same way `recorder` attaches it to the muxed stream's codec
+/// parameters. Without this, streams that don't repeat their parameter sets in-band (typical
+/// for HEVC) fail to decode. Returns `false` if the allocation failed.
+fn attach_extradata(codec_context: *mut av::AVCodecContext, extradata: &[u8]) -> bool {
+    let buffer = unsafe { av::av_malloc(extradata.len()) } as *mut u8;
+    if buffer.is_null() {
+        return false;
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(extradata.as_ptr(), buffer, extradata.len());
+        (*codec_context).extradata = buffer;
+        (*codec_context).extradata_size = extradata.len() as c_int;
+    }
+
+    true
+}
+
+/// Tries each hardware decode backend in `av::HW_DEVICE_TYPES_IN_PRIORITY_ORDER`, returning the
+/// first device context that initializes, or `None` if every backend fails and software decode
+/// should be used instead.
+fn create_hw_device_ctx() -> Option<*mut av::AVBufferRef> {
+    for device_type in av::HW_DEVICE_TYPES_IN_PRIORITY_ORDER {
+        let mut device_ctx = ptr::null_mut();
+        let ret = unsafe {
+            av::av_hwdevice_ctx_create(
+                &mut device_ctx,
+                device_type,
+                ptr::null(),
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if ret >= 0 && !device_ctx.is_null() {
+            return Some(device_ctx);
+        }
+    }
+
+    None
+}
+
+/// Decodes the mirrored bitstream and streams downscaled JPEG frames over the existing
+/// websocket layer so the dashboard can show what the headset is actually receiving, without
+/// putting on the headset. Runs on the web server runtime so it never blocks `video_send`.
+///
+/// The codec is read from `crate::negotiated_codec()` right after the decoder config arrives
+/// (i.e. after the handshake has already set it), rather than being fixed at spawn time, since
+/// `preview_loop` is started once at driver init, before any client has connected.
+pub async fn preview_loop(
+    mut mirror_receiver: broadcast::Receiver<Vec<u8>>,
+    jpeg_sender: broadcast::Sender<Vec<u8>>,
+) {
+    let Ok(extradata) = mirror_receiver.recv().await else {
+        warn!("Preview decode stopped before decoder config was available");
+        return;
+    };
+
+    let codec_id = crate::negotiated_codec();
+    let codec = unsafe { av::avcodec_find_decoder(codec_id) };
+    if codec.is_null() {
+        error!("No decoder available for codec id {codec_id}");
+        return;
+    }
+
+    let codec_context = unsafe { av::avcodec_alloc_context3(codec) };
+    if codec_context.is_null() {
+        error!("Failed to allocate decoder context for preview");
+        return;
+    }
+
+    if !attach_extradata(codec_context, &extradata) {
+        error!("Failed to allocate extradata buffer for preview decoder");
+        return;
+    }
+
+    let mut hw_device_ctx = create_hw_device_ctx();
+    if let Some(ctx) = hw_device_ctx {
+        unsafe { (*codec_context).hw_device_ctx = av::av_buffer_ref(ctx) };
+    } else {
+        info!("No hardware decoder available, falling back to software decode for preview");
+    }
+
+    if unsafe { av::avcodec_open2(codec_context, codec, ptr::null_mut()) } < 0 {
+        error!("Failed to open preview decoder");
+        if let Some(ctx) = &mut hw_device_ctx {
+            unsafe { av::av_buffer_unref(ctx) };
+        }
+        return;
+    }
+
+    let mut last_frame_time = Instant::now() - THROTTLE_INTERVAL;
+    loop {
+        let Ok(mut payload) = mirror_receiver.recv().await else {
+            break;
+        };
+
+        if last_frame_time.elapsed() < THROTTLE_INTERVAL {
+            continue;
+        }
+        last_frame_time = Instant::now();
+
+        let packet = unsafe { av::av_packet_alloc() };
+        if packet.is_null() {
+            continue;
+        }
+        unsafe {
+            (*packet).data = payload.as_mut_ptr();
+            (*packet).size = payload.len() as c_int;
+        }
+
+        if unsafe { av::avcodec_send_packet(codec_context, packet) } >= 0 {
+            let decoded_frame = unsafe { av::av_frame_alloc() };
+            if !decoded_frame.is_null() {
+                while unsafe { av::avcodec_receive_frame(codec_context, decoded_frame) } >= 0 {
+                    let frame_to_encode = if hw_device_ctx.is_some() {
+                        let sw_frame = unsafe { av::av_frame_alloc() };
+                        if !sw_frame.is_null() {
+                            unsafe { av::av_hwframe_transfer_data(sw_frame, decoded_frame, 0) };
+                        }
+                        sw_frame
+                    } else {
+                        decoded_frame
+                    };
+
+                    if !frame_to_encode.is_null() {
+                        if let Some(jpeg) = encode_preview_jpeg(frame_to_encode) {
+                            jpeg_sender.send(jpeg).ok();
+                        }
+
+                        if frame_to_encode != decoded_frame {
+                            let mut owned = frame_to_encode;
+                            unsafe { av::av_frame_free(&mut owned) };
+                        }
+                    }
+                }
+
+                let mut owned = decoded_frame;
+                unsafe { av::av_frame_free(&mut owned) };
+            }
+        }
+
+        unsafe { av::av_packet_free(&mut (packet as *mut av::AVPacket)) };
+    }
+
+    if let Some(ctx) = &mut hw_device_ctx {
+        unsafe { av::av_buffer_unref(ctx) };
+    }
+}
+
+/// swscale-converts the decoded frame to a downscaled RGB buffer and JPEG-encodes it for the
+/// websocket.
+fn encode_preview_jpeg(frame: *mut av::AVFrame) -> Option<Vec<u8>> {
+    let (src_width, src_height, src_format) =
+        unsafe { ((*frame).width, (*frame).height, (*frame).format) };
+    if src_width <= 0 || src_height <= 0 {
+        return None;
+    }
+
+    let dst_width = PREVIEW_WIDTH.min(src_width);
+    let dst_height = (src_height as f32 * (dst_width as f32 / src_width as f32)) as c_int;
+
+    let sws_ctx = unsafe {
+        av::sws_getContext(
+            src_width,
+            src_height,
+            src_format,
+            dst_width,
+            dst_height,
+            av::AV_PIX_FMT_RGB24,
+            av::SWS_BILINEAR,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null(),
+        )
+    };
+    if sws_ctx.is_null() {
+        return None;
+    }
+
+    let mut rgb_buffer = vec![0u8; (dst_width * dst_height * 3) as usize];
+    let dst_stride = dst_width * 3;
+
+    let ret = unsafe {
+        let src_data: Vec<*const u8> = (*frame).data.iter().map(|p| *p as *const u8).collect();
+        let src_stride: Vec<c_int> = (*frame).linesize.to_vec();
+        av::sws_scale(
+            sws_ctx,
+            src_data.as_ptr(),
+            src_stride.as_ptr(),
+            0,
+            src_height,
+            [rgb_buffer.as_mut_ptr()].as_ptr(),
+            [dst_stride].as_ptr(),
+        )
+    };
+
+    unsafe { av::sws_freeContext(sws_ctx) };
+
+    if ret <= 0 {
+        return None;
+    }
+
+    let mut jpeg_bytes = Vec::new();
+    let image_buffer =
+        image::RgbImage::from_raw(dst_width as u32, dst_height as u32, rgb_buffer)?;
+    let mut encoder = image::codecs::jpeg::JpegEncoder::new(&mut jpeg_bytes);
+    encoder
+        .encode_image(&image::DynamicImage::ImageRgb8(image_buffer))
+        .ok()?;
+
+    Some(jpeg_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hw_device_fallback_tries_cuda_before_vaapi() {
+        assert_eq!(
+            av::HW_DEVICE_TYPES_IN_PRIORITY_ORDER,
+            [av::AV_HWDEVICE_TYPE_CUDA, av::AV_HWDEVICE_TYPE_VAAPI]
+        );
+    }
+}
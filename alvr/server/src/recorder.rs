@@ -0,0 +1,386 @@
+use alvr_common::prelude::*;
+use std::{
+    ffi::CString,
+    os::raw::{c_char, c_int},
+    path::{Path, PathBuf},
+    ptr,
+};
+use tokio::sync::broadcast;
+
+// Minimal libavformat/libavcodec/libavutil surface needed to remux an already-encoded Annex B
+// bitstream into a fragmented container. Mirrors the style of the `bindings` module: just
+// enough of the C API to do the job, hand-declared instead of full bindgen since only this
+// file needs it.
+#[allow(non_camel_case_types)]
+mod av {
+    use super::*;
+
+    pub const AVMEDIA_TYPE_VIDEO: c_int = 0;
+    pub const AV_PKT_FLAG_KEY: c_int = 1;
+    pub const AVIO_FLAG_WRITE: c_int = 2;
+
+    #[repr(C)]
+    pub struct AVIOContext {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct AVFormatContext {
+        pub pb: *mut AVIOContext,
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct AVCodecParameters {
+        pub codec_type: c_int,
+        pub codec_id: c_int,
+        pub extradata: *mut u8,
+        pub extradata_size: c_int,
+        pub width: c_int,
+        pub height: c_int,
+    }
+
+    #[repr(C)]
+    pub struct AVStream {
+        pub codecpar: *mut AVCodecParameters,
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct AVPacket {
+        pub data: *mut u8,
+        pub size: c_int,
+        pub pts: i64,
+        pub dts: i64,
+        pub stream_index: c_int,
+        pub flags: c_int,
+        _private: [u8; 0],
+    }
+
+    extern "C" {
+        pub fn avformat_alloc_output_context2(
+            ctx: *mut *mut AVFormatContext,
+            oformat: *const std::ffi::c_void,
+            format_name: *const c_char,
+            filename: *const c_char,
+        ) -> c_int;
+        pub fn avformat_new_stream(
+            ctx: *mut AVFormatContext,
+            c: *const std::ffi::c_void,
+        ) -> *mut AVStream;
+        pub fn avio_open(ctx: *mut *mut AVIOContext, url: *const c_char, flags: c_int) -> c_int;
+        pub fn avio_closep(ctx: *mut *mut AVIOContext) -> c_int;
+        pub fn avformat_write_header(
+            ctx: *mut AVFormatContext,
+            opts: *mut *mut std::ffi::c_void,
+        ) -> c_int;
+        pub fn av_write_frame(ctx: *mut AVFormatContext, pkt: *mut AVPacket) -> c_int;
+        pub fn av_write_trailer(ctx: *mut AVFormatContext) -> c_int;
+        pub fn avformat_free_context(ctx: *mut AVFormatContext);
+        pub fn av_packet_alloc() -> *mut AVPacket;
+        pub fn av_packet_free(pkt: *mut *mut AVPacket);
+        pub fn av_packet_from_data(pkt: *mut AVPacket, data: *mut u8, size: c_int) -> c_int;
+        pub fn av_malloc(size: usize) -> *mut std::ffi::c_void;
+    }
+}
+
+/// Timestamps, in the container's stream timebase (90kHz, the usual choice for video-only
+/// containers), derived from `video_frame_index` and the negotiated target framerate. The
+/// first frame seen defines PTS 0 so recordings that start mid-session still begin at zero.
+fn compute_pts(video_frame_index: u64, first_frame_index: u64, target_framerate: f32) -> i64 {
+    ((video_frame_index - first_frame_index) as f64 * 90_000.0 / target_framerate as f64) as i64
+}
+
+/// Muxes the raw mirror bitstream (the same encoded frames sent to the client) into a
+/// fragmented MP4 or Matroska file, so the user can capture gameplay without a second
+/// capture tool. The first `InitializeDecoder` config buffer becomes the stream extradata;
+/// every later mirror buffer is wrapped as an `AVPacket` with a PTS/DTS derived from
+/// `video_frame_index` and the negotiated target framerate, with keyframes flagged.
+pub struct Recorder {
+    format_context: *mut av::AVFormatContext,
+    target_framerate: f32,
+    first_frame_index: Option<u64>,
+}
+
+unsafe impl Send for Recorder {}
+
+impl Recorder {
+    fn new(
+        output_path: &Path,
+        codec_id: i32,
+        width: i32,
+        height: i32,
+        extradata: &[u8],
+        target_framerate: f32,
+    ) -> StrResult<Self> {
+        let mut format_context = ptr::null_mut();
+        let path_cstring =
+            CString::new(output_path.to_string_lossy().as_bytes()).map_err(err_to_string)?;
+
+        let ret = unsafe {
+            av::avformat_alloc_output_context2(
+                &mut format_context,
+                ptr::null(),
+                ptr::null(),
+                path_cstring.as_ptr(),
+            )
+        };
+        if ret < 0 || format_context.is_null() {
+            return fmt_e!("Failed to allocate output format context (error {ret})");
+        }
+
+        let stream = unsafe { av::avformat_new_stream(format_context, ptr::null()) };
+        if stream.is_null() {
+            unsafe { av::avformat_free_context(format_context) };
+            return fmt_e!("Failed to create output video stream");
+        }
+
+        if let Err(e) = attach_codec_parameters(stream, codec_id, width, height, extradata) {
+            unsafe { av::avformat_free_context(format_context) };
+            return Err(e);
+        }
+
+        let mut io_context = ptr::null_mut();
+        let ret =
+            unsafe { av::avio_open(&mut io_context, path_cstring.as_ptr(), av::AVIO_FLAG_WRITE) };
+        if ret < 0 {
+            unsafe { av::avformat_free_context(format_context) };
+            return fmt_e!("Failed to open recording output file (error {ret})");
+        }
+        unsafe { (*format_context).pb = io_context };
+
+        let ret = unsafe { av::avformat_write_header(format_context, ptr::null_mut()) };
+        if ret < 0 {
+            unsafe {
+                av::avio_closep(&mut (*format_context).pb);
+                av::avformat_free_context(format_context);
+            }
+            return fmt_e!("Failed to write container header (error {ret})");
+        }
+
+        Ok(Self {
+            format_context,
+            target_framerate,
+            first_frame_index: None,
+        })
+    }
+
+    fn write_frame(
+        &mut self,
+        video_frame_index: u64,
+        is_keyframe: bool,
+        mut payload: Vec<u8>,
+    ) -> StrResult {
+        let first_frame_index = *self.first_frame_index.get_or_insert(video_frame_index);
+        let pts = compute_pts(video_frame_index, first_frame_index, self.target_framerate);
+
+        let packet = unsafe { av::av_packet_alloc() };
+        if packet.is_null() {
+            return fmt_e!("Failed to allocate AVPacket");
+        }
+
+        let ret = unsafe {
+            av::av_packet_from_data(packet, payload.as_mut_ptr(), payload.len() as c_int)
+        };
+        if ret < 0 {
+            unsafe { av::av_packet_free(&mut (packet as *mut av::AVPacket)) };
+            return fmt_e!("Failed to wrap frame buffer in AVPacket (error {ret})");
+        }
+        // payload's backing memory is now owned by the AVPacket; avoid double-freeing it here.
+        std::mem::forget(payload);
+
+        unsafe {
+            (*packet).pts = pts;
+            (*packet).dts = pts;
+            (*packet).stream_index = 0;
+            (*packet).flags = if is_keyframe { av::AV_PKT_FLAG_KEY } else { 0 };
+        }
+
+        let ret = unsafe { av::av_write_frame(self.format_context, packet) };
+        unsafe { av::av_packet_free(&mut (packet as *mut av::AVPacket)) };
+
+        if ret < 0 {
+            return fmt_e!("Failed to mux frame (error {ret})");
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies `extradata` (SPS/PPS, plus VPS for HEVC) onto the stream's codec parameters so
+/// decoders can find it without re-parsing every keyframe, and records the codec id and frame
+/// dimensions so the container knows what it's holding.
+fn attach_codec_parameters(
+    stream: *mut av::AVStream,
+    codec_id: i32,
+    width: i32,
+    height: i32,
+    extradata: &[u8],
+) -> StrResult {
+    let codecpar = unsafe { (*stream).codecpar };
+    if codecpar.is_null() {
+        return fmt_e!("Stream has no codec parameters to attach extradata to");
+    }
+
+    let extradata_buffer = unsafe { av::av_malloc(extradata.len()) } as *mut u8;
+    if extradata_buffer.is_null() {
+        return fmt_e!("Failed to allocate extradata buffer");
+    }
+    unsafe {
+        ptr::copy_nonoverlapping(extradata.as_ptr(), extradata_buffer, extradata.len());
+
+        (*codecpar).codec_type = av::AVMEDIA_TYPE_VIDEO;
+        (*codecpar).codec_id = codec_id;
+        (*codecpar).extradata = extradata_buffer;
+        (*codecpar).extradata_size = extradata.len() as c_int;
+        (*codecpar).width = width;
+        (*codecpar).height = height;
+    }
+
+    Ok(())
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        unsafe {
+            av::av_write_trailer(self.format_context);
+            av::avio_closep(&mut (*self.format_context).pb);
+            av::avformat_free_context(self.format_context);
+        }
+    }
+}
+
+/// Drives a `Recorder` from the mirror broadcast until stopped or the broadcast closes.
+///
+/// `extradata` is the decoder config buffer for the session currently streaming, handed in by
+/// the caller rather than read off `mirror_receiver`: recording is started well after that
+/// buffer was broadcast once over `VIDEO_MIRROR_SENDER`, and `broadcast` channels don't replay
+/// past messages to a subscriber that joins late.
+pub async fn recording_loop(
+    output_path: PathBuf,
+    codec_id: i32,
+    width: i32,
+    height: i32,
+    extradata: Vec<u8>,
+    target_framerate: f32,
+    mut mirror_receiver: broadcast::Receiver<Vec<u8>>,
+    mut stop_receiver: tokio::sync::oneshot::Receiver<()>,
+) {
+    let mut recorder = match Recorder::new(
+        &output_path,
+        codec_id,
+        width,
+        height,
+        &extradata,
+        target_framerate,
+    ) {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            error!("Failed to start recording to {output_path:?}: {e}");
+            return;
+        }
+    };
+
+    let mut video_frame_index = 0;
+    loop {
+        tokio::select! {
+            _ = &mut stop_receiver => break,
+            frame = mirror_receiver.recv() => {
+                match frame {
+                    Ok(payload) => {
+                        let is_keyframe = payload_is_keyframe(codec_id, &payload);
+                        if let Err(e) = recorder.write_frame(video_frame_index, is_keyframe, payload) {
+                            error!("Failed to write recorded frame: {e}");
+                            break;
+                        }
+                        video_frame_index += 1;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                }
+            }
+        }
+    }
+
+    // `recorder` is dropped here, flushing and finalizing the container trailer so an
+    // abruptly ended recording stays playable.
+}
+
+/// Scans an Annex B-formatted frame for a NAL unit type that marks it as a keyframe: H.264 IDR
+/// (type 5) or an HEVC IRAP picture (types 16-23), the same definition FFmpeg demuxers use to
+/// set `AV_PKT_FLAG_KEY`. Unrecognized codec ids are treated as never containing a keyframe
+/// rather than guessing, since muxing a non-key frame as key breaks seeking worse than the
+/// reverse.
+fn payload_is_keyframe(codec_id: i32, payload: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset + 3 < payload.len() {
+        let start_code_len = if payload[offset..].starts_with(&[0, 0, 0, 1]) {
+            4
+        } else if payload[offset..].starts_with(&[0, 0, 1]) {
+            3
+        } else {
+            offset += 1;
+            continue;
+        };
+
+        let nal_start = offset + start_code_len;
+        let Some(&nal_header) = payload.get(nal_start) else {
+            break;
+        };
+
+        let is_keyframe_nal = match codec_id {
+            crate::AV_CODEC_ID_HEVC => (16..=23).contains(&((nal_header >> 1) & 0x3f)),
+            crate::AV_CODEC_ID_H264 => nal_header & 0x1f == 5,
+            _ => false,
+        };
+        if is_keyframe_nal {
+            return true;
+        }
+
+        offset = nal_start;
+    }
+
+    false
+}
+
+fn err_to_string<E: std::fmt::Display>(e: E) -> String {
+    e.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pts_starts_at_zero_for_the_first_recorded_frame() {
+        assert_eq!(compute_pts(42, 42, 72.0), 0);
+    }
+
+    #[test]
+    fn pts_advances_by_the_frame_period_in_the_90khz_timebase() {
+        // At 72 fps one frame period is 90_000 / 72 = 1250 timebase ticks.
+        assert_eq!(compute_pts(43, 42, 72.0), 1250);
+        assert_eq!(compute_pts(44, 42, 72.0), 2500);
+    }
+
+    #[test]
+    fn h264_idr_nal_is_detected_as_keyframe() {
+        // start code + NAL header 0x65 = forbidden_zero_bit 0, nal_ref_idc 11, type 5 (IDR).
+        let payload = [0, 0, 0, 1, 0x65, 0xAA, 0xBB];
+        assert!(payload_is_keyframe(crate::AV_CODEC_ID_H264, &payload));
+    }
+
+    #[test]
+    fn h264_non_idr_nal_is_not_a_keyframe() {
+        // type 1 (non-IDR slice).
+        let payload = [0, 0, 0, 1, 0x41, 0xAA, 0xBB];
+        assert!(!payload_is_keyframe(crate::AV_CODEC_ID_H264, &payload));
+    }
+
+    #[test]
+    fn hevc_irap_nal_is_detected_as_keyframe() {
+        // NAL header's first byte encodes type in bits 1-6; type 19 (IDR_W_RADL) -> 0x26.
+        let payload = [0, 0, 1, 0x26, 0x01, 0xAA, 0xBB];
+        assert!(payload_is_keyframe(crate::AV_CODEC_ID_HEVC, &payload));
+    }
+}
@@ -0,0 +1,119 @@
+use alvr_common::prelude::*;
+use alvr_events::EventType;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::{net::SocketAddr, path::PathBuf};
+use tokio::sync::broadcast;
+use warp::Filter;
+
+/// Size of every broadcast channel handed out by `lib.rs` (logs, legacy/structured events, the
+/// video mirror, the preview JPEG stream): enough to absorb a short burst without dropping
+/// frames for a client that's briefly behind, without growing unbounded while nobody's
+/// subscribed.
+pub const WS_BROADCAST_CAPACITY: usize = 256;
+
+const WEB_SERVER_PORT: u16 = 8082;
+
+/// Body of `POST /api/recording/start`. Mirrors the parameters `crate::start_recording` needs to
+/// open the output container for the stream currently being negotiated.
+#[derive(Deserialize)]
+struct StartRecordingRequest {
+    output_path: PathBuf,
+    codec_id: i32,
+    width: i32,
+    height: i32,
+    target_framerate: f32,
+}
+
+/// Relays the dashboard's logs/events over websockets and exposes the recording controls the
+/// dashboard's "Record" button drives. Runs until the webserver runtime is torn down.
+pub async fn web_server(
+    log_sender: broadcast::Sender<String>,
+    legacy_events_sender: broadcast::Sender<EventType>,
+    events_sender: broadcast::Sender<EventType>,
+) -> StrResult {
+    let log_ws = warp::path!("api" / "log")
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| {
+            let mut receiver = log_sender.subscribe();
+            ws.on_upgrade(move |socket| async move {
+                let (mut sender, _receiver) = socket.split();
+                while let Ok(line) = receiver.recv().await {
+                    if sender.send(warp::ws::Message::text(line)).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .boxed();
+
+    let legacy_events_ws = warp::path!("api" / "events" / "legacy")
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| {
+            let mut receiver = legacy_events_sender.subscribe();
+            ws.on_upgrade(move |socket| async move {
+                let (mut sender, _receiver) = socket.split();
+                while let Ok(event) = receiver.recv().await {
+                    let Ok(json) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if sender.send(warp::ws::Message::text(json)).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .boxed();
+
+    let events_ws = warp::path!("api" / "events")
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| {
+            let mut receiver = events_sender.subscribe();
+            ws.on_upgrade(move |socket| async move {
+                let (mut sender, _receiver) = socket.split();
+                while let Ok(event) = receiver.recv().await {
+                    let Ok(json) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if sender.send(warp::ws::Message::text(json)).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .boxed();
+
+    let start_recording = warp::path!("api" / "recording" / "start")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(|request: StartRecordingRequest| {
+            crate::start_recording(
+                request.output_path,
+                request.codec_id,
+                request.width,
+                request.height,
+                request.target_framerate,
+            );
+            warp::reply()
+        })
+        .boxed();
+
+    let stop_recording = warp::path!("api" / "recording" / "stop")
+        .and(warp::post())
+        .map(|| {
+            crate::stop_recording();
+            warp::reply()
+        })
+        .boxed();
+
+    let routes = log_ws
+        .or(legacy_events_ws)
+        .or(events_ws)
+        .or(start_recording)
+        .or(stop_recording);
+
+    let address: SocketAddr = ([0, 0, 0, 0], WEB_SERVER_PORT).into();
+    warp::serve(routes).run(address).await;
+
+    Ok(())
+}